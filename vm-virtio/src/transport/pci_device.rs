@@ -14,22 +14,24 @@ extern crate vmm_sys_util;
 
 use byteorder::{ByteOrder, LittleEndian};
 use libc::EFD_NONBLOCK;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use devices::BusDevice;
+use pci::msix::{MsixCap, MsixConfig, MsixConfigState, MSIX_TABLE_ENTRY_SIZE};
 use pci::{
     PciBarConfiguration, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice,
     PciDeviceError, PciHeaderType, PciInterruptPin, PciSubclass,
 };
 use vm_allocator::SystemAllocator;
 use vm_memory::{Address, ByteValued, GuestAddress, GuestMemoryMmap, GuestUsize, Le32};
-use vmm_sys_util::{EventFd, Result};
+use vmm_sys_util::{errno::Error, EventFd, Result};
 
 use super::VirtioPciCommonConfig;
 use crate::{
     Queue, VirtioDevice, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
-    DEVICE_FEATURES_OK, DEVICE_INIT,
+    DEVICE_FEATURES_OK, DEVICE_INIT, DEVICE_NEEDS_RESET,
 };
 
 #[allow(clippy::enum_variant_names)]
@@ -39,6 +41,7 @@ enum PciCapabilityType {
     IsrConfig = 3,
     DeviceConfig = 4,
     PciConfig = 5,
+    SharedMemoryConfig = 8,
 }
 
 #[allow(dead_code)]
@@ -122,6 +125,93 @@ impl VirtioPciNotifyCap {
     }
 }
 
+// VIRTIO_PCI_CAP_PCI_CFG: a BAR window reachable through PCI config space.
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct VirtioPciCfgCap {
+    cap: VirtioPciCap,
+    pci_cfg_data: [u8; 4],
+}
+// It is safe to implement ByteValued. All members are simple numbers and any value is valid.
+unsafe impl ByteValued for VirtioPciCfgCap {}
+
+impl PciCapability for VirtioPciCfgCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::VendorSpecific
+    }
+}
+
+impl VirtioPciCfgCap {
+    fn new() -> Self {
+        VirtioPciCfgCap {
+            cap: VirtioPciCap {
+                cap_len: std::mem::size_of::<VirtioPciCfgCap>() as u8,
+                cfg_type: PciCapabilityType::PciConfig as u8,
+                pci_bar: 0,
+                padding: [0; 3],
+                offset: Le32::from(0),
+                length: Le32::from(0),
+            },
+            pci_cfg_data: [0; 4],
+        }
+    }
+}
+
+// VIRTIO_PCI_CAP_SHARED_MEMORY_CFG (type 8): a 64-bit-capable shared-memory
+// region, identified by an id byte packed into the capability's padding.
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct VirtioPciCap64 {
+    cap: VirtioPciCap,
+    offset_hi: Le32,
+    length_hi: Le32,
+}
+// It is safe to implement ByteValued. All members are simple numbers and any value is valid.
+unsafe impl ByteValued for VirtioPciCap64 {}
+
+impl PciCapability for VirtioPciCap64 {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::VendorSpecific
+    }
+}
+
+impl VirtioPciCap64 {
+    pub fn new(pci_bar: u8, id: u8, offset: u64, length: u64) -> Self {
+        VirtioPciCap64 {
+            cap: VirtioPciCap {
+                cap_len: std::mem::size_of::<VirtioPciCap64>() as u8,
+                cfg_type: PciCapabilityType::SharedMemoryConfig as u8,
+                pci_bar,
+                padding: [id, 0, 0],
+                offset: Le32::from(offset as u32),
+                length: Le32::from(length as u32),
+            },
+            offset_hi: Le32::from((offset >> 32) as u32),
+            length_hi: Le32::from((length >> 32) as u32),
+        }
+    }
+}
+
+/// A shared-memory region backed by a `VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`
+/// capability. `guest_addr` is filled in by `allocate_bars`.
+#[derive(Clone, Copy)]
+pub struct VirtioShmRegion {
+    pub id: u8,
+    pub size: GuestUsize,
+    pub host_addr: u64,
+    pub guest_addr: GuestAddress,
+}
+
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 pub enum PciVirtioSubclass {
@@ -139,17 +229,74 @@ const COMMON_CONFIG_BAR_OFFSET: u64 = 0x0000;
 const COMMON_CONFIG_SIZE: u64 = 56;
 const ISR_CONFIG_BAR_OFFSET: u64 = 0x1000;
 const ISR_CONFIG_SIZE: u64 = 1;
+// ISR register bit 1: a configuration change (e.g. DEVICE_NEEDS_RESET) is pending.
+const ISR_CONFIG_CHANGED: usize = 0x2;
 const DEVICE_CONFIG_BAR_OFFSET: u64 = 0x2000;
 const DEVICE_CONFIG_SIZE: u64 = 0x1000;
 const NOTIFICATION_BAR_OFFSET: u64 = 0x3000;
 const NOTIFICATION_SIZE: u64 = 0x1000;
-const CAPABILITY_BAR_SIZE: u64 = 0x4000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x4000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x5000;
+const CAPABILITY_BAR_SIZE: u64 = 0x6000;
 
 const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+// Per the virtio spec, a queue (or the device config) that is not bound to
+// any MSI-X vector uses this sentinel value.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// Rounds `value` up to the next multiple of `align`, a power of two.
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+// The `(bar_reg, offset_reg, length_reg, data_reg)` dword-register indices
+// of a VIRTIO_PCI_CAP_PCI_CFG capability starting at `cap_offset`.
+fn pci_cfg_window_regs(cap_offset: usize) -> (usize, usize, usize, usize) {
+    (
+        (cap_offset + 4) / 4,
+        (cap_offset + 8) / 4,
+        (cap_offset + 12) / 4,
+        (cap_offset + 16) / 4,
+    )
+}
+
+/// Per-queue state captured by `VirtioPciDevice::save` and reapplied by
+/// `VirtioPciDevice::restore`.
+#[derive(Clone, Serialize, Deserialize)]
+struct QueueState {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+}
+
+/// Snapshot of a `VirtioPciDevice`'s transport state, suitable for
+/// serializing and shipping to another host for live migration. The
+/// device-specific config blob is opaque here and round-tripped through the
+/// backing `VirtioDevice`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioPciDeviceState {
+    device_activated: bool,
+    driver_status: u8,
+    config_generation: u8,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u16,
+    interrupt_status: usize,
+    settings_bar: u8,
+    config_msix_vector: u16,
+    queues: Vec<QueueState>,
+    // MSI-X table state (addresses/data/mask bits), if MSI-X is set up.
+    msix_state: Option<MsixConfigState>,
+    device_state: Option<Vec<u8>>,
+}
+
 pub struct VirtioPciDevice {
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -165,6 +312,16 @@ pub struct VirtioPciDevice {
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<EventFd>,
 
+    // MSI-X config, shared with the device backend.
+    msix_config: Option<Arc<Mutex<MsixConfig>>>,
+    msix_num_vectors: u16,
+
+    // Set by `pause`/cleared by `resume`.
+    paused: Arc<AtomicBool>,
+
+    // Set by the device backend to request a driver-visible reset.
+    device_needs_reset: Arc<AtomicBool>,
+
     // virtio queues
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
@@ -174,6 +331,12 @@ pub struct VirtioPciDevice {
 
     // Setting PCI BAR
     settings_bar: u8,
+
+    // Shared-memory regions (VIRTIO_PCI_CAP_SHARED_MEMORY_CFG), if any.
+    shm_regions: Vec<VirtioShmRegion>,
+
+    // Config space offset of the VIRTIO_PCI_CAP_PCI_CFG capability, or 0.
+    cap_pci_cfg_offset: usize,
 }
 
 impl VirtioPciDevice {
@@ -210,15 +373,22 @@ impl VirtioPciDevice {
                 device_feature_select: 0,
                 driver_feature_select: 0,
                 queue_select: 0,
+                config_msix_vector: VIRTIO_MSI_NO_VECTOR,
             },
             device,
             device_activated: false,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: None,
+            msix_config: None,
+            msix_num_vectors: 0,
+            paused: Arc::new(AtomicBool::new(false)),
+            device_needs_reset: Arc::new(AtomicBool::new(false)),
             queues,
             queue_evts,
             memory: Some(memory),
             settings_bar: 0,
+            shm_regions: Vec::new(),
+            cap_pci_cfg_offset: 0,
         })
     }
 
@@ -229,11 +399,166 @@ impl VirtioPciDevice {
         self.queue_evts.as_slice()
     }
 
-    /// Gets the event this device uses to interrupt the VM when the used queue is changed.
+    /// Gets the event this device uses to interrupt the VM over the legacy INTx line. Only
+    /// used while the driver has not enabled MSI-X.
     pub fn interrupt_evt(&self) -> Option<&EventFd> {
         self.interrupt_evt.as_ref()
     }
 
+    /// Gets the per-vector EventFds backing the MSI-X table, one per queue plus one for
+    /// device configuration changes, so the VMM can register them as irqfds once the driver
+    /// enables MSI-X.
+    pub fn msix_vectors(&self) -> Option<Vec<EventFd>> {
+        self.msix_config
+            .as_ref()
+            .map(|cfg| cfg.lock().unwrap().irqfds())
+    }
+
+    /// Gets the device's shared-memory regions, with `guest_addr` set once
+    /// `allocate_bars` has placed them.
+    pub fn shm_regions(&self) -> &[VirtioShmRegion] {
+        &self.shm_regions
+    }
+
+    /// Gets the flag the device backend trips to request a driver reset.
+    pub fn needs_reset(&self) -> Arc<AtomicBool> {
+        self.device_needs_reset.clone()
+    }
+
+    // Latches a pending `device_needs_reset` request into `driver_status`
+    // and notifies the guest, per the virtio spec's DEVICE_NEEDS_RESET flow.
+    fn poll_needs_reset(&mut self) {
+        if self.device_needs_reset.swap(false, Ordering::SeqCst) {
+            self.common_config.driver_status |= DEVICE_NEEDS_RESET as u8;
+            self.common_config.config_generation =
+                self.common_config.config_generation.wrapping_add(1);
+            self.signal_config_changed();
+        }
+    }
+
+    // Raises a configuration-change interrupt: the device's config MSI-X
+    // vector if one is bound, otherwise the ISR config-change bit over INTx.
+    fn signal_config_changed(&self) {
+        if let Some(msix_config) = self.msix_config.as_ref() {
+            let vector = self.common_config.config_msix_vector;
+            if vector != VIRTIO_MSI_NO_VECTOR {
+                msix_config.lock().unwrap().trigger(vector);
+                return;
+            }
+        }
+
+        self.interrupt_status
+            .fetch_or(ISR_CONFIG_CHANGED, Ordering::SeqCst);
+        if let Some(interrupt_evt) = self.interrupt_evt.as_ref() {
+            let _ = interrupt_evt.write(1);
+        }
+    }
+
+    /// Stops the device backend from signalling `queue_evts`/interrupts so the
+    /// used ring can't advance while a snapshot is being serialized. Call
+    /// `resume` once `save` has returned.
+    pub fn pause(&mut self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets the device backend resume signalling after a `pause`/`save`.
+    pub fn resume(&mut self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Captures the transport state (and, via the device's own config blob,
+    /// the backend's state) needed to reconstruct this device elsewhere.
+    /// The caller is expected to have paused the device first.
+    pub fn save(&self) -> VirtioPciDeviceState {
+        VirtioPciDeviceState {
+            device_activated: self.device_activated,
+            driver_status: self.common_config.driver_status,
+            config_generation: self.common_config.config_generation,
+            device_feature_select: self.common_config.device_feature_select,
+            driver_feature_select: self.common_config.driver_feature_select,
+            queue_select: self.common_config.queue_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            settings_bar: self.settings_bar,
+            config_msix_vector: self.common_config.config_msix_vector,
+            queues: self
+                .queues
+                .iter()
+                .map(|queue| QueueState {
+                    max_size: queue.max_size,
+                    size: queue.size,
+                    ready: queue.ready,
+                    desc_table: queue.desc_table.raw_value(),
+                    avail_ring: queue.avail_ring.raw_value(),
+                    used_ring: queue.used_ring.raw_value(),
+                })
+                .collect(),
+            msix_state: self
+                .msix_config
+                .as_ref()
+                .map(|cfg| cfg.lock().unwrap().save()),
+            device_state: self.device.save(),
+        }
+    }
+
+    /// Rebuilds transport state from a snapshot taken by `save`, re-running
+    /// `activate` against the restored queues if it was active.
+    pub fn restore(&mut self, state: VirtioPciDeviceState) -> Result<()> {
+        self.common_config.driver_status = state.driver_status;
+        self.common_config.config_generation = state.config_generation;
+        self.common_config.device_feature_select = state.device_feature_select;
+        self.common_config.driver_feature_select = state.driver_feature_select;
+        self.common_config.queue_select = state.queue_select;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.settings_bar = state.settings_bar;
+        self.common_config.config_msix_vector = state.config_msix_vector;
+
+        for (queue, saved) in self.queues.iter_mut().zip(state.queues.iter()) {
+            queue.size = saved.size;
+            queue.ready = saved.ready;
+            queue.desc_table = GuestAddress(saved.desc_table);
+            queue.avail_ring = GuestAddress(saved.avail_ring);
+            queue.used_ring = GuestAddress(saved.used_ring);
+        }
+
+        self.device.restore(state.device_state)?;
+
+        if let (Some(msix_config), Some(msix_state)) =
+            (self.msix_config.as_ref(), state.msix_state.as_ref())
+        {
+            msix_config.lock().unwrap().restore(msix_state.clone());
+        }
+
+        if state.device_activated {
+            match (self.interrupt_evt.as_ref(), self.memory.as_ref()) {
+                (Some(interrupt_evt), Some(mem)) => {
+                    let mem = mem.clone();
+                    let interrupt_evt = interrupt_evt
+                        .try_clone()
+                        .expect("Failed to clone interrupt_evt");
+                    self.device.activate(
+                        mem,
+                        interrupt_evt,
+                        self.interrupt_status.clone(),
+                        self.msix_config.clone(),
+                        self.paused.clone(),
+                        self.device_needs_reset.clone(),
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )?;
+                    self.device_activated = true;
+                }
+                _ => {
+                    // Not enough set up yet to reactivate; don't silently
+                    // leave the restored device looking inactive.
+                    return Err(Error::new(libc::EINVAL));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits =
             (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
@@ -301,15 +626,91 @@ impl VirtioPciDevice {
             .add_capability(&notify_cap)
             .map_err(PciDeviceError::CapabilitiesSetup)?;
 
-        //TODO(dgreid) - How will the configuration_cap work?
-        let configuration_cap = VirtioPciCap::new(PciCapabilityType::PciConfig, 0, 0, 0);
-        self.configuration
+        // VIRTIO_PCI_CAP_PCI_CFG. `pci_bar`/`offset`/`length` start out
+        // zeroed; the driver programs them before each access through
+        // `pci_cfg_data`, handled in `write_config_register` and
+        // `read_config_register`.
+        let configuration_cap = VirtioPciCfgCap::new();
+        self.cap_pci_cfg_offset = self
+            .configuration
             .add_capability(&configuration_cap)
             .map_err(PciDeviceError::CapabilitiesSetup)?;
 
+        // One vector per queue, plus one for device configuration change
+        // notifications.
+        let msix_num_vectors = self.queues.len() as u16 + 1;
+        let msix_cap = MsixCap::new(
+            settings_bar,
+            msix_num_vectors,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            settings_bar,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        self.configuration
+            .add_capability(&msix_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+
+        self.msix_config = Some(Arc::new(Mutex::new(MsixConfig::new(msix_num_vectors))));
+        self.msix_num_vectors = msix_num_vectors;
+
         self.settings_bar = settings_bar;
         Ok(())
     }
+
+    // `Some((data_reg, bar_offset, len))` if `reg_idx` is `pci_cfg_data` and
+    // the window points at a BAR this transport can serve, else `None`.
+    fn pci_cfg_window_target(&self, reg_idx: usize) -> Option<(usize, u64, usize)> {
+        if self.cap_pci_cfg_offset == 0 {
+            return None;
+        }
+
+        let (bar_reg, offset_reg, length_reg, data_reg) =
+            pci_cfg_window_regs(self.cap_pci_cfg_offset);
+        if reg_idx != data_reg {
+            return None;
+        }
+
+        let bar = self.config_registers().read_reg(bar_reg) as u8;
+        if bar != self.settings_bar {
+            return None;
+        }
+
+        let bar_offset = u64::from(self.config_registers().read_reg(offset_reg));
+        let len = (self.config_registers().read_reg(length_reg) as usize).min(4);
+        if len == 0 {
+            return None;
+        }
+
+        Some((data_reg, bar_offset, len))
+    }
+
+    // A write to `pci_cfg_data` forwards to the targeted BAR, then refreshes
+    // the register from the live BAR contents.
+    fn update_pci_cfg_window(&mut self, reg_idx: usize) {
+        let (data_reg, bar_offset, len) = match self.pci_cfg_window_target(reg_idx) {
+            Some(target) => target,
+            None => return,
+        };
+
+        let value = self.config_registers().read_reg(data_reg);
+        self.write_bar(bar_offset, &value.to_le_bytes()[..len]);
+        self.refresh_pci_cfg_data(data_reg, bar_offset, len);
+    }
+
+    // Pulls live BAR contents into `pci_cfg_data` before a plain config-space
+    // read of it, with no preceding write, reads the register back out.
+    fn refresh_pci_cfg_window_on_read(&mut self, reg_idx: usize) {
+        if let Some((data_reg, bar_offset, len)) = self.pci_cfg_window_target(reg_idx) {
+            self.refresh_pci_cfg_data(data_reg, bar_offset, len);
+        }
+    }
+
+    fn refresh_pci_cfg_data(&mut self, data_reg: usize, bar_offset: u64, len: usize) {
+        let mut bytes = [0u8; 4];
+        self.read_bar(bar_offset, &mut bytes[..len]);
+        self.config_registers_mut()
+            .write_reg(data_reg, u32::from_le_bytes(bytes));
+    }
 }
 
 impl PciDevice for VirtioPciDevice {
@@ -370,6 +771,7 @@ impl PciDevice for VirtioPciDevice {
         self.add_pci_capabilities(virtio_pci_bar)?;
 
         // Allocate the device specific BARs.
+        let mut next_bar_index = 1;
         for config in self.device.get_device_bars() {
             let device_bar_addr = allocator
                 .allocate_mmio_addresses(None, config.get_size())
@@ -379,12 +781,53 @@ impl PciDevice for VirtioPciDevice {
                 PciDeviceError::IoRegistrationFailed(device_bar_addr.raw_value(), e)
             })?;
             ranges.push((device_bar_addr, config.get_size()));
+            next_bar_index += 1;
+        }
+
+        // Allocate a single 64-bit BAR sized for every shared-memory region
+        // the device reports, each placed at a page-aligned offset so it can
+        // be installed as its own KVM user-memory slot.
+        let mut shm_regions: Vec<VirtioShmRegion> = self.device.get_shm_regions();
+        if !shm_regions.is_empty() {
+            let page_size = 0x1000;
+            let mut total_size = 0u64;
+            for region in &shm_regions {
+                total_size += align_up(region.size, page_size);
+            }
+
+            let shm_bar_addr = allocator
+                .allocate_mmio_addresses(None, total_size)
+                .ok_or(PciDeviceError::IoAllocationFailed(total_size))?;
+            let config = PciBarConfiguration::default()
+                .set_register_index(next_bar_index)
+                .set_address(shm_bar_addr.raw_value())
+                .set_size(total_size)
+                .set_64bit_memory(true);
+            let shm_bar = self.configuration.add_pci_bar(&config).map_err(|e| {
+                PciDeviceError::IoRegistrationFailed(shm_bar_addr.raw_value(), e)
+            })? as u8;
+
+            ranges.push((shm_bar_addr, total_size));
+
+            let mut region_offset = 0u64;
+            for region in shm_regions.iter_mut() {
+                let cap = VirtioPciCap64::new(shm_bar, region.id, region_offset, region.size);
+                self.configuration
+                    .add_capability(&cap)
+                    .map_err(PciDeviceError::CapabilitiesSetup)?;
+                region.guest_addr = shm_bar_addr.checked_add(region_offset).unwrap();
+                region_offset += align_up(region.size, page_size);
+            }
+
+            self.shm_regions = shm_regions;
         }
 
         Ok(ranges)
     }
 
     fn read_bar(&mut self, offset: u64, data: &mut [u8]) {
+        self.poll_needs_reset();
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -408,6 +851,25 @@ impl PciDevice for VirtioPciDevice {
             {
                 // Handled with ioeventfds.
             }
+            o if MSIX_TABLE_BAR_OFFSET <= o
+                && o < MSIX_TABLE_BAR_OFFSET
+                    + u64::from(MSIX_TABLE_ENTRY_SIZE) * u64::from(self.msix_num_vectors) =>
+            {
+                if let Some(msix_config) = self.msix_config.as_ref() {
+                    msix_config
+                        .lock()
+                        .unwrap()
+                        .read_table(o - MSIX_TABLE_BAR_OFFSET, data);
+                }
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + 0x1000 => {
+                if let Some(msix_config) = self.msix_config.as_ref() {
+                    msix_config
+                        .lock()
+                        .unwrap()
+                        .read_pba(o - MSIX_PBA_BAR_OFFSET, data);
+                }
+            }
             _ => (),
         }
     }
@@ -436,18 +898,52 @@ impl PciDevice for VirtioPciDevice {
             {
                 // Handled with ioeventfds.
             }
+            o if MSIX_TABLE_BAR_OFFSET <= o
+                && o < MSIX_TABLE_BAR_OFFSET
+                    + u64::from(MSIX_TABLE_ENTRY_SIZE) * u64::from(self.msix_num_vectors) =>
+            {
+                if let Some(msix_config) = self.msix_config.as_ref() {
+                    msix_config
+                        .lock()
+                        .unwrap()
+                        .write_table(o - MSIX_TABLE_BAR_OFFSET, data);
+                }
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + 0x1000 => {
+                if let Some(msix_config) = self.msix_config.as_ref() {
+                    msix_config
+                        .lock()
+                        .unwrap()
+                        .write_pba(o - MSIX_PBA_BAR_OFFSET, data);
+                }
+            }
             _ => (),
         };
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(interrupt_evt) = self.interrupt_evt.take() {
+            if let Some(interrupt_evt) = self.interrupt_evt.as_ref() {
                 if self.memory.is_some() {
                     let mem = self.memory.as_ref().unwrap().clone();
+                    // Hand the backend a clone rather than taking the EventFd:
+                    // `signal_config_changed` still needs it to raise the
+                    // legacy INTx config-change interrupt for NEEDS_RESET
+                    // after activation.
+                    let interrupt_evt = interrupt_evt
+                        .try_clone()
+                        .expect("Failed to clone interrupt_evt");
+                    // Per-queue vector binding (`queue_msix_vector`) is
+                    // expected to live in `VirtioPciCommonConfig`/`Queue`,
+                    // outside this file; handing `activate()` both `queues`
+                    // and `msix_config` is what would let it look that
+                    // binding up and signal per-queue vectors.
                     self.device
                         .activate(
                             mem,
                             interrupt_evt,
                             self.interrupt_status.clone(),
+                            self.msix_config.clone(),
+                            self.paused.clone(),
+                            self.device_needs_reset.clone(),
                             self.queues.clone(),
                             self.queue_evts.split_off(0),
                         )
@@ -457,11 +953,18 @@ impl PciDevice for VirtioPciDevice {
             }
         }
 
-        // Device has been reset by the driver
+        // Device has been reset by the driver. Check this before
+        // `poll_needs_reset` latches a concurrently-requested device-side
+        // reset back onto `driver_status`: otherwise a driver write that
+        // clears `driver_status` to DEVICE_INIT in the same cycle the
+        // backend trips `device_needs_reset` would have its own reset
+        // request silently dropped until some later, unrelated access
+        // happened to retry it.
         if self.device_activated && self.is_driver_init() {
-            if let Some((interrupt_evt, mut queue_evts)) = self.device.reset() {
-                // Upon reset the device returns its interrupt EventFD and it's queue EventFDs
-                self.interrupt_evt = Some(interrupt_evt);
+            if let Some((_interrupt_evt, mut queue_evts)) = self.device.reset() {
+                // The device hands back its clone of the interrupt EventFD
+                // and its queue EventFDs; `self.interrupt_evt` already holds
+                // the original, so only the queue EventFDs need reclaiming.
                 self.queue_evts.append(&mut queue_evts);
 
                 self.device_activated = false;
@@ -475,6 +978,8 @@ impl PciDevice for VirtioPciDevice {
                 self.common_config.driver_status = crate::DEVICE_FAILED as u8;
             }
         }
+
+        self.poll_needs_reset();
     }
 }
 
@@ -492,20 +997,70 @@ impl BusDevice for VirtioPciDevice {
             return;
         }
 
-        let regs = self.config_registers_mut();
+        {
+            let regs = self.config_registers_mut();
 
-        match data.len() {
-            1 => regs.write_byte(reg_idx * 4 + offset as usize, data[0]),
-            2 => regs.write_word(
-                reg_idx * 4 + offset as usize,
-                u16::from(data[0]) | (u16::from(data[1]) << 8),
-            ),
-            4 => regs.write_reg(reg_idx, LittleEndian::read_u32(data)),
-            _ => (),
+            match data.len() {
+                1 => regs.write_byte(reg_idx * 4 + offset as usize, data[0]),
+                2 => regs.write_word(
+                    reg_idx * 4 + offset as usize,
+                    u16::from(data[0]) | (u16::from(data[1]) << 8),
+                ),
+                4 => regs.write_reg(reg_idx, LittleEndian::read_u32(data)),
+                _ => (),
+            }
         }
+
+        self.update_pci_cfg_window(reg_idx);
     }
 
-    fn read_config_register(&self, reg_idx: usize) -> u32 {
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.refresh_pci_cfg_window_on_read(reg_idx);
         self.config_registers().read_reg(reg_idx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_next_page() {
+        let page = 0x1000;
+        assert_eq!(align_up(0, page), 0);
+        assert_eq!(align_up(1, page), page);
+        assert_eq!(align_up(page, page), page);
+        assert_eq!(align_up(page + 1, page), 2 * page);
+        assert_eq!(align_up(0x2345, page), 0x3000);
+    }
+
+    #[test]
+    fn shm_region_offsets_are_page_aligned() {
+        let page = 0x1000;
+        let sizes = [0x1000u64, 0x1234, 0x4000, 1];
+        let mut total = 0u64;
+        let mut offsets = Vec::new();
+        for size in sizes.iter() {
+            offsets.push(total);
+            total += align_up(*size, page);
+        }
+
+        assert_eq!(offsets, vec![0, 0x1000, 0x3000, 0x7000]);
+        assert_eq!(total, 0x8000);
+        for offset in &offsets {
+            assert_eq!(offset % page, 0);
+        }
+    }
+
+    #[test]
+    fn pci_cfg_window_regs_derives_dword_indices() {
+        // cap_vndr/cap_next/cap_len/cfg_type share the first dword, so a
+        // capability placed right at the start of config space still lands
+        // bar/offset/length/pci_cfg_data on the following four dwords.
+        assert_eq!(pci_cfg_window_regs(0), (1, 2, 3, 4));
+
+        // A capability further out in config space shifts every register by
+        // the same byte offset, still truncated down to its dword.
+        assert_eq!(pci_cfg_window_regs(0x40), (17, 18, 19, 20));
+    }
+}